@@ -1,6 +1,8 @@
 use crate::*;
 use bevy::prelude::*;
-use ldtk_rust::{TileInstance, TilesetDefinition};
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::sprite::Anchor;
+use ldtk_rust::{LayerDefinition, Rect, TileInstance, TilesetDefinition};
 use serde_json::Value;
 use std::collections::HashMap;
 
@@ -33,14 +35,30 @@ pub struct LdtkEntityTile {
     pub tileset_uid: i64,
 }
 
+/// Tracks the top-level entities spawned for a map entity's current level(s), so they can be
+/// despawned when the underlying [LdtkAsset] reloads or the [LevelSelection] changes.
+#[derive(Clone, Debug, Default, Component)]
+struct SpawnedLevelChildren(Vec<Entity>);
+
 const CHUNK_SIZE: ChunkSize = ChunkSize(32, 32);
 
 pub fn process_loaded_ldtk(
     mut commands: Commands,
     mut ldtk_events: EventReader<AssetEvent<LdtkAsset>>,
-    mut ldtk_map_query: Query<(Entity, &Handle<LdtkAsset>, &LevelSelection, &mut Map)>,
+    mut ldtk_map_query: Query<(
+        Entity,
+        &Handle<LdtkAsset>,
+        &LevelSelection,
+        &mut Map,
+        Option<&SpawnedLevelChildren>,
+    )>,
     ldtk_assets: Res<Assets<LdtkAsset>>,
     new_ldtks: Query<&Handle<LdtkAsset>, Added<Handle<LdtkAsset>>>,
+    asset_server: Res<AssetServer>,
+    ldtk_settings: Res<LdtkSettings>,
+    mut clear_color: ResMut<ClearColor>,
+    mut map_query: MapQuery,
+    mut images: ResMut<Assets<Image>>,
 ) {
     // This function uses code from the bevy_ecs_tilemap ldtk example
     // https://github.com/StarArawn/bevy_ecs_tilemap/blob/main/examples/ldtk/ldtk.rs
@@ -72,72 +90,502 @@ pub fn process_loaded_ldtk(
     }
 
     for changed_ldtk in changed_ldtks.iter() {
-        for (entity, ldtk_handle, level_selection, mut map) in ldtk_map_query
+        for (entity, ldtk_handle, level_selection, mut map, previously_spawned) in ldtk_map_query
             .iter_mut()
-            .filter(|(_, l, _, _)| changed_ldtk == *l)
+            .filter(|(_, l, _, _, _)| changed_ldtk == *l)
         {
-            //TODO: despawn changed levels
-
-            let ldtk_asset = ldtk_assets.get(ldtk_handle).unwrap();
-
-            let tileset_definition_map: HashMap<i64, &TilesetDefinition> = ldtk_asset
-                .project
-                .defs
-                .tilesets
-                .iter()
-                .map(|t| (t.uid, t))
-                .collect();
-
-            for (_, level) in
-                ldtk_asset.project.levels.iter().enumerate().filter(
-                    |(i, l)| match level_selection {
-                        LevelSelection::Identifier(s) => *s == l.identifier,
-                        LevelSelection::Index(j) => j == i,
-                        LevelSelection::Uid(u) => *u == l.uid,
-                    },
-                )
-            {
-                if let Some(layer_instances) = &level.layer_instances {
-                    for (layer_z, layer_instance) in layer_instances.into_iter().rev().enumerate() {
-                        if let Some(tileset_uid) = layer_instance.tileset_def_uid {
-                            let map_size = MapSize(
-                                (layer_instance.c_wid as f32 / CHUNK_SIZE.0 as f32).ceil() as u32,
-                                (layer_instance.c_hei as f32 / CHUNK_SIZE.1 as f32).ceil() as u32,
-                            );
-
-                            let tileset_definition =
-                                tileset_definition_map.get(&tileset_uid).unwrap();
-                            let mut settings = LayerSettings::new(
-                                map_size,
-                                CHUNK_SIZE,
-                                TileSize(
-                                    tileset_definition.tile_grid_size as f32,
-                                    tileset_definition.tile_grid_size as f32,
-                                ),
-                                TextureSize(
-                                    tileset_definition.px_wid as f32,
-                                    tileset_definition.px_hei as f32,
-                                ),
-                            );
-                            let (mut layer_builder, layer_entity) = LayerBuilder::<TileBundle>::new(
-                                &mut commands,
-                                settings,
-                                map.id,
-                                layer_z as u16,
-                                None,
-                            );
-                            for tile in &layer_instance.auto_layer_tiles {}
-                            for tile in &layer_instance.grid_tiles {}
+            spawn_level(
+                &mut commands,
+                entity,
+                ldtk_handle,
+                level_selection,
+                &mut map,
+                previously_spawned,
+                &ldtk_assets,
+                &asset_server,
+                &ldtk_settings,
+                &mut clear_color,
+                &mut map_query,
+                &mut images,
+            );
+        }
+    }
+}
+
+/// Detects changes to the [LevelSelection] on map entities and respawns the newly selected
+/// level(s) in place of whatever was previously spawned there.
+///
+/// Entities whose [Handle<LdtkAsset>] was just added are skipped here, even though adding
+/// [LevelSelection] alongside it also counts as a change: `process_loaded_ldtk` already spawns
+/// their initial level off the asset-created/new-handle path, and running both in the same frame
+/// would spawn the level twice while only tracking the second batch in [SpawnedLevelChildren],
+/// orphaning the first.
+pub fn apply_level_selection(
+    mut commands: Commands,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
+    asset_server: Res<AssetServer>,
+    ldtk_settings: Res<LdtkSettings>,
+    mut clear_color: ResMut<ClearColor>,
+    mut ldtk_map_query: Query<
+        (
+            Entity,
+            &Handle<LdtkAsset>,
+            &LevelSelection,
+            &mut Map,
+            Option<&SpawnedLevelChildren>,
+        ),
+        Changed<LevelSelection>,
+    >,
+    new_ldtks: Query<Entity, Added<Handle<LdtkAsset>>>,
+    mut map_query: MapQuery,
+    mut images: ResMut<Assets<Image>>,
+) {
+    for (entity, ldtk_handle, level_selection, mut map, previously_spawned) in
+        ldtk_map_query.iter_mut()
+    {
+        if new_ldtks.contains(entity) {
+            continue;
+        }
+
+        spawn_level(
+            &mut commands,
+            entity,
+            ldtk_handle,
+            level_selection,
+            &mut map,
+            previously_spawned,
+            &ldtk_assets,
+            &asset_server,
+            &ldtk_settings,
+            &mut clear_color,
+            &mut map_query,
+            &mut images,
+        );
+    }
+}
+
+/// Despawns the level(s) previously spawned for `entity` (tracked via [SpawnedLevelChildren]) and
+/// spawns whichever level(s) `level_selection` now points at, rebuilding layers, IntGrid cells,
+/// entities, and backgrounds from the current state of `ldtk_handle`'s [LdtkAsset].
+#[allow(clippy::too_many_arguments)]
+fn spawn_level(
+    commands: &mut Commands,
+    entity: Entity,
+    ldtk_handle: &Handle<LdtkAsset>,
+    level_selection: &LevelSelection,
+    map: &mut Map,
+    previously_spawned: Option<&SpawnedLevelChildren>,
+    ldtk_assets: &Assets<LdtkAsset>,
+    asset_server: &AssetServer,
+    ldtk_settings: &LdtkSettings,
+    clear_color: &mut ClearColor,
+    map_query: &mut MapQuery,
+    images: &mut Assets<Image>,
+) {
+    if let Some(previously_spawned) = previously_spawned {
+        for child in previously_spawned.0.iter() {
+            commands.entity(*child).despawn_recursive();
+        }
+    }
+
+    let mut spawned_children = Vec::<Entity>::new();
+
+    let ldtk_asset = match ldtk_assets.get(ldtk_handle) {
+        Some(ldtk_asset) => ldtk_asset,
+        // The asset hasn't finished loading yet; there's nothing to (re)spawn this frame.
+        None => {
+            commands
+                .entity(entity)
+                .insert(SpawnedLevelChildren(spawned_children));
+            return;
+        }
+    };
+
+    let tileset_definition_map: HashMap<i64, &TilesetDefinition> = ldtk_asset
+        .project
+        .defs
+        .tilesets
+        .iter()
+        .map(|t| (t.uid, t))
+        .collect();
+
+    let layer_definition_map: HashMap<i64, &LayerDefinition> = ldtk_asset
+        .project
+        .defs
+        .layers
+        .iter()
+        .map(|l| (l.uid, l))
+        .collect();
+
+    let tileset_map: &HashMap<i64, Handle<Image>> = &ldtk_asset.tileset_map;
+
+    for (_, level) in ldtk_asset
+        .project
+        .levels
+        .iter()
+        .enumerate()
+        .filter(|(i, l)| match level_selection {
+            LevelSelection::Identifier(s) => *s == l.identifier,
+            LevelSelection::Index(j) => j == i,
+            LevelSelection::Uid(u) => *u == l.uid,
+        })
+    {
+        let bg_color = hex_to_color(&level.bg_color);
+        // Like the tile layers (see `add_tile_to_layer`), the level's own origin is its
+        // bottom-left corner in a Y-up world, while LDtk's `px`/`__bgPos` fields are all
+        // top-left-origin, Y-down. `level_height_px` converts between the two.
+        let level_height_px = level.px_hei as f32;
+
+        if ldtk_settings.set_clear_color == SetClearColor::FromLevelBackground {
+            clear_color.0 = bg_color;
+        } else {
+            commands.entity(entity).with_children(|parent| {
+                let background_entity = parent
+                    .spawn(SpriteBundle {
+                        sprite: Sprite {
+                            color: bg_color,
+                            custom_size: Some(Vec2::new(level.px_wid as f32, level.px_hei as f32)),
+                            anchor: Anchor::BottomLeft,
+                            ..Default::default()
+                        },
+                        transform: Transform::from_xyz(0., 0., -10.),
+                        ..Default::default()
+                    })
+                    .id();
+                spawned_children.push(background_entity);
+            });
+        }
+
+        if let (Some(bg_rel_path), Some(bg_pos)) = (&level.bg_rel_path, &level.bg_pos) {
+            let background_image = asset_server.load(bg_rel_path.as_str());
+
+            let crop_x = bg_pos.crop_rect[0] as f32;
+            let crop_y = bg_pos.crop_rect[1] as f32;
+            let crop_width = bg_pos.crop_rect[2] as f32;
+            let crop_height = bg_pos.crop_rect[3] as f32;
+
+            let scale_x = bg_pos.scale[0] as f32;
+            let scale_y = bg_pos.scale[1] as f32;
+
+            let top_left_x = bg_pos.top_left_px[0] as f32;
+            let top_left_y = bg_pos.top_left_px[1] as f32;
+
+            commands.entity(entity).with_children(|parent| {
+                let background_image_entity = parent
+                    .spawn(SpriteBundle {
+                        sprite: Sprite {
+                            rect: Some(bevy::sprite::Rect {
+                                min: Vec2::new(crop_x, crop_y),
+                                max: Vec2::new(crop_x + crop_width, crop_y + crop_height),
+                            }),
+                            custom_size: Some(Vec2::new(
+                                crop_width * scale_x,
+                                crop_height * scale_y,
+                            )),
+                            anchor: Anchor::TopLeft,
+                            ..Default::default()
+                        },
+                        texture: background_image,
+                        transform: Transform::from_xyz(
+                            top_left_x,
+                            level_height_px - top_left_y,
+                            -9.,
+                        ),
+                        ..Default::default()
+                    })
+                    .id();
+                spawned_children.push(background_image_entity);
+            });
+        }
+
+        if let Some(layer_instances) = &level.layer_instances {
+            // IntGrid sublayers get their own layer id, offset past every tile/AutoLayer layer
+            // id, so an IntGrid+AutoLayer combo layer doesn't collide with itself.
+            let int_grid_layer_id_offset = layer_instances.len() as u16;
+
+            // IntGrid cells are tinted via `Tile.color`, which modulates a sampled texel rather
+            // than standing in for one, so they need a real (albeit featureless) texture behind
+            // them or the color multiplies against nothing and the cells render invisible.
+            // Lazily created since not every level has an IntGrid layer.
+            let mut white_pixel: Option<Handle<Image>> = None;
+
+            for (layer_z, layer_instance) in layer_instances.into_iter().rev().enumerate() {
+                if let Some(tileset_uid) = layer_instance.tileset_def_uid {
+                    let map_size = MapSize(
+                        (layer_instance.c_wid as f32 / CHUNK_SIZE.0 as f32).ceil() as u32,
+                        (layer_instance.c_hei as f32 / CHUNK_SIZE.1 as f32).ceil() as u32,
+                    );
+
+                    let tileset_definition = tileset_definition_map.get(&tileset_uid).unwrap();
+                    let mut settings = LayerSettings::new(
+                        map_size,
+                        CHUNK_SIZE,
+                        TileSize(
+                            tileset_definition.tile_grid_size as f32,
+                            tileset_definition.tile_grid_size as f32,
+                        ),
+                        TextureSize(
+                            tileset_definition.px_wid as f32,
+                            tileset_definition.px_hei as f32,
+                        ),
+                    );
+                    let (mut layer_builder, layer_entity) = LayerBuilder::<TileBundle>::new(
+                        &mut commands,
+                        settings,
+                        map.id,
+                        layer_z as u16,
+                        None,
+                    );
+                    for tile in &layer_instance.auto_layer_tiles {
+                        add_tile_to_layer(
+                            tile,
+                            tileset_definition,
+                            layer_instance.c_hei,
+                            layer_instance.grid_size,
+                            &mut layer_builder,
+                        );
+                    }
+                    for tile in &layer_instance.grid_tiles {
+                        add_tile_to_layer(
+                            tile,
+                            tileset_definition,
+                            layer_instance.c_hei,
+                            layer_instance.grid_size,
+                            &mut layer_builder,
+                        );
+                    }
+
+                    let tileset_image = tileset_map.get(&tileset_uid).cloned().unwrap_or_default();
+                    map_query.build_layer(commands, layer_builder, tileset_image);
+                    map.add_layer(commands, layer_z as u16, layer_entity);
+
+                    spawned_children.push(layer_entity);
+                }
+
+                if !layer_instance.int_grid_csv.is_empty() {
+                    let layer_definition = layer_definition_map
+                        .get(&layer_instance.layer_def_uid)
+                        .unwrap();
+
+                    let value_colors: HashMap<i64, Color> = layer_definition
+                        .int_grid_values
+                        .iter()
+                        .map(|v| (v.value, hex_to_color(&v.color)))
+                        .collect();
+
+                    let map_size = MapSize(
+                        (layer_instance.c_wid as f32 / CHUNK_SIZE.0 as f32).ceil() as u32,
+                        (layer_instance.c_hei as f32 / CHUNK_SIZE.1 as f32).ceil() as u32,
+                    );
+                    let settings = LayerSettings::new(
+                        map_size,
+                        CHUNK_SIZE,
+                        TileSize(
+                            layer_instance.grid_size as f32,
+                            layer_instance.grid_size as f32,
+                        ),
+                        TextureSize(
+                            layer_instance.grid_size as f32,
+                            layer_instance.grid_size as f32,
+                        ),
+                    );
+                    let int_grid_layer_id = layer_z as u16 + int_grid_layer_id_offset;
+                    let (mut layer_builder, layer_entity) = LayerBuilder::<TileBundle>::new(
+                        &mut commands,
+                        settings,
+                        map.id,
+                        int_grid_layer_id,
+                        None,
+                    );
+
+                    for (i, value) in layer_instance.int_grid_csv.iter().enumerate() {
+                        if *value == 0 {
+                            continue;
                         }
 
-                        for cell in &layer_instance.int_grid_csv {}
+                        let color = match value_colors.get(value) {
+                            Some(color) => *color,
+                            None => continue,
+                        };
+
+                        let grid_x = i as i64 % layer_instance.c_wid;
+                        let grid_y = layer_instance.c_hei - 1 - i as i64 / layer_instance.c_wid;
+                        let tile_pos = TilePos(grid_x as u32, grid_y as u32);
 
-                        for entity_instance in &layer_instance.entity_instances {}
+                        let tile_entity = layer_builder
+                            .set_tile(
+                                tile_pos,
+                                TileBundle {
+                                    tile: Tile {
+                                        color,
+                                        ..Default::default()
+                                    },
+                                    ..Default::default()
+                                },
+                            )
+                            .unwrap();
+
+                        commands.entity(tile_entity).insert(LdtkIntGridCell(*value));
                     }
+
+                    let white_pixel = white_pixel.get_or_insert_with(|| {
+                        images.add(Image::new_fill(
+                            Extent3d {
+                                width: 1,
+                                height: 1,
+                                depth_or_array_layers: 1,
+                            },
+                            TextureDimension::D2,
+                            &[255, 255, 255, 255],
+                            TextureFormat::Rgba8UnormSrgb,
+                        ))
+                    });
+                    map_query.build_layer(commands, layer_builder, white_pixel.clone());
+                    map.add_layer(commands, int_grid_layer_id, layer_entity);
+
+                    spawned_children.push(layer_entity);
+                }
+
+                for entity_instance in &layer_instance.entity_instances {
+                    let tile = entity_instance.tile.as_ref().map(|t| LdtkEntityTile {
+                        src_rect: Rect {
+                            left: t.src_rect[0],
+                            top: t.src_rect[1],
+                            right: t.src_rect[0] + t.src_rect[2],
+                            bottom: t.src_rect[1] + t.src_rect[3],
+                        },
+                        tileset_uid: t.tileset_uid,
+                    });
+
+                    let ldtk_entity = LdtkEntity {
+                        grid: IVec2::new(
+                            entity_instance.grid[0] as i32,
+                            entity_instance.grid[1] as i32,
+                        ),
+                        identifier: entity_instance.identifier.clone(),
+                        pivot: Vec2::new(
+                            entity_instance.pivot[0] as f32,
+                            entity_instance.pivot[1] as f32,
+                        ),
+                        tile,
+                        def_uid: entity_instance.def_uid,
+                        field_instances: entity_instance
+                            .field_instances
+                            .iter()
+                            .map(|f| LdtkField {
+                                identifier: f.identifier.clone(),
+                                value: f.value.clone(),
+                                def_uid: f.def_uid,
+                            })
+                            .collect(),
+                        height: entity_instance.height,
+                        px: IVec2::new(entity_instance.px[0] as i32, entity_instance.px[1] as i32),
+                        width: entity_instance.width,
+                    };
+
+                    // Entities are spawned as children of the map entity, like backgrounds and
+                    // tile layers above, so they inherit the map's transform and despawn with it.
+                    commands.entity(entity).with_children(|parent| {
+                        let mut entity_commands = parent.spawn(ldtk_entity.clone());
+
+                        if let Some(tile) = &ldtk_entity.tile {
+                            if let Some(image) = tileset_map.get(&tile.tileset_uid) {
+                                let width = (tile.src_rect.right - tile.src_rect.left) as f32;
+                                let height = (tile.src_rect.bottom - tile.src_rect.top) as f32;
+
+                                // LDtk's px is top-left-origin, Y-down, but tile layers (see
+                                // `add_tile_to_layer`) place grid row 0 at the top of a
+                                // bottom-left-origin, Y-up map, so entity Y has to be mirrored
+                                // against the level height to land on the same cell. The pivot
+                                // then offsets the sprite so that (0.5, 0.5) centers it and (0, 0)
+                                // anchors its top-left corner.
+                                let level_height_px =
+                                    layer_instance.c_hei as f32 * layer_instance.grid_size as f32;
+                                let translation = Vec3::new(
+                                    ldtk_entity.px.x as f32 + width * (0.5 - ldtk_entity.pivot.x),
+                                    level_height_px
+                                        - ldtk_entity.px.y as f32
+                                        - height * (0.5 - ldtk_entity.pivot.y),
+                                    layer_z as f32,
+                                );
+
+                                entity_commands.insert(SpriteBundle {
+                                    sprite: Sprite {
+                                        rect: Some(bevy::sprite::Rect {
+                                            min: Vec2::new(
+                                                tile.src_rect.left as f32,
+                                                tile.src_rect.top as f32,
+                                            ),
+                                            max: Vec2::new(
+                                                tile.src_rect.right as f32,
+                                                tile.src_rect.bottom as f32,
+                                            ),
+                                        }),
+                                        anchor: Anchor::Center,
+                                        ..Default::default()
+                                    },
+                                    texture: image.clone(),
+                                    transform: Transform::from_translation(translation),
+                                    ..Default::default()
+                                });
+                            }
+                        }
+
+                        spawned_children.push(entity_commands.id());
+                    });
                 }
             }
         }
     }
+
+    commands
+        .entity(entity)
+        .insert(SpawnedLevelChildren(spawned_children));
+}
+
+/// Converts a single LDtk [TileInstance] into a [TileBundle] and inserts it into `layer_builder`,
+/// decoding the tile's `f` flip flags and translating its `src` pixel position into an atlas
+/// texture index.
+fn add_tile_to_layer(
+    tile: &TileInstance,
+    tileset_definition: &TilesetDefinition,
+    c_hei: i64,
+    grid_size: i64,
+    layer_builder: &mut LayerBuilder<TileBundle>,
+) {
+    let tiles_per_row = tileset_definition.px_wid / tileset_definition.tile_grid_size;
+    let tileset_x = tile.src[0] / tileset_definition.tile_grid_size;
+    let tileset_y = tile.src[1] / tileset_definition.tile_grid_size;
+    let texture_index = (tileset_y * tiles_per_row + tileset_x) as u16;
+
+    // Bit 0 of `f` is a horizontal flip, bit 1 is a vertical flip.
+    let flip_x = tile.f & 1 == 1;
+    let flip_y = (tile.f >> 1) & 1 == 1;
+
+    let grid_x = tile.px[0] / grid_size;
+    let grid_y = c_hei - 1 - tile.px[1] / grid_size;
+
+    layer_builder
+        .set_tile(
+            TilePos(grid_x as u32, grid_y as u32),
+            TileBundle {
+                tile: Tile {
+                    texture_index,
+                    flip_x,
+                    flip_y,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
 }
 
-fn add_tile_to_layer(tile: &TileInstance, layer: &mut LayerBuilder<TileBundle>) {}
+/// Parses a `"#rrggbb"` LDtk color string into a Bevy [Color], falling back to black and logging
+/// a warning if the string isn't valid, rather than panicking on malformed project data.
+fn hex_to_color(hex: &str) -> Color {
+    Color::hex(hex.trim_start_matches('#')).unwrap_or_else(|_| {
+        warn!("encountered invalid LDtk color string: {hex}");
+        Color::BLACK
+    })
+}